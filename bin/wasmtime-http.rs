@@ -1,8 +1,15 @@
 use ::tokio;
 use anyhow::{bail, Error};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use structopt::StructOpt;
 use wasi_experimental_http_wasmtime::HttpCtx;
-use wasmtime::{Config, Engine, Func, Instance, Linker, Store, Val, ValType};
+use wasmtime::{
+    Config, Engine, ExternType, Func, Instance, Linker, Memory, Module, Store, Val, ValType,
+};
 use wasmtime_wasi::*;
 
 #[derive(Debug, StructOpt)]
@@ -31,7 +38,9 @@ struct Opt {
     #[structopt(
         short = "a",
         long = "allowed-host",
-        help = "Host the guest module is allowed to make outbound HTTP requests to"
+        help = "Host the guest module is allowed to make outbound HTTP requests to. \
+                Accepts a wildcard subdomain (e.g. `https://*.example.com:8443`) or a bare \
+                `*` label (e.g. `https://api.*.brigade.sh`) in addition to exact hosts"
     )]
     allowed_hosts: Option<Vec<String>>,
 
@@ -42,39 +51,488 @@ struct Opt {
     )]
     max_concurrency: Option<u32>,
 
+    #[structopt(
+        long = "request-timeout",
+        value_name = "MS",
+        help = "Time, in milliseconds, to wait for a single outbound request to an allowed host \
+                before failing it, distinct from --deadline's total invocation budget"
+    )]
+    request_timeout: Option<u64>,
+
+    #[structopt(
+        long = "deadline",
+        value_name = "MS",
+        help = "Total wall-clock time, in milliseconds, allotted to the whole invocation"
+    )]
+    deadline: Option<u64>,
+
+    #[structopt(
+        long = "decompress",
+        help = "Transparently decompress gzip/br/deflate/zstd response bodies before handing \
+                them to the guest"
+    )]
+    decompress: bool,
+
+    #[structopt(
+        long = "fuel",
+        default_value = "10000",
+        help = "The amount of fuel to give the instance up front. Each time it runs out, the \
+                instance yields and is refuelled with --fuel-yield-interval more, with no limit \
+                on the number of refuels, so it never traps for lack of fuel"
+    )]
+    fuel: u64,
+
+    #[structopt(
+        long = "fuel-yield-interval",
+        default_value = "10000",
+        help = "How much fuel to consume between async yield points"
+    )]
+    fuel_yield_interval: u64,
+
     #[structopt(value_name = "ARGS", help = "The arguments to pass to the module")]
     module_args: Vec<String>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Serve inbound HTTP requests by dispatching each one to a guest export,
+    /// instead of invoking the module once and exiting.
+    Serve {
+        #[structopt(
+            long = "handler",
+            default_value = "handle_request",
+            help = "The name of the guest export to call for every inbound request"
+        )]
+        handler: String,
+
+        #[structopt(
+            long = "listen",
+            default_value = "127.0.0.1:3000",
+            help = "The address to bind the inbound HTTP listener to"
+        )]
+        listen: SocketAddr,
+    },
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Error> {
     let opt = Opt::from_args();
-    let method = opt.invoke.clone();
     // println!("{:?}", opt);
-    let (instance, mut store) =
-        create_instance(opt.module, opt.vars, opt.allowed_hosts, opt.max_concurrency).await?;
-    let func = instance
-        .get_func(&mut store, method.as_str())
-        .unwrap_or_else(|| panic!("cannot find function {}", method));
 
-    invoke_func(func, opt.module_args, &mut store).await?;
+    match opt.cmd {
+        Some(Command::Serve { handler, listen }) => {
+            serve(
+                opt.module,
+                opt.vars,
+                opt.allowed_hosts,
+                opt.max_concurrency,
+                opt.request_timeout,
+                opt.decompress,
+                opt.fuel,
+                opt.fuel_yield_interval,
+                opt.deadline,
+                handler,
+                listen,
+            )
+            .await
+        }
+        None => {
+            let method = opt.invoke.clone();
+            let (instance, mut store) = create_instance(
+                opt.module,
+                opt.vars,
+                opt.allowed_hosts,
+                opt.max_concurrency,
+                opt.request_timeout,
+                opt.decompress,
+                opt.fuel,
+                opt.fuel_yield_interval,
+            )
+            .await?;
+            let func = instance
+                .get_func(&mut store, method.as_str())
+                .unwrap_or_else(|| panic!("cannot find function {}", method));
 
+            let result =
+                with_deadline(opt.deadline, invoke_func(func, opt.module_args, &mut store)).await;
+
+            if let Some(consumed) = store.fuel_consumed() {
+                eprintln!("fuel consumed: {}", consumed);
+            }
+
+            result
+        }
+    }
+}
+
+/// Run `fut` to completion, or fail it with a deadline-exceeded error once
+/// `deadline_ms` milliseconds elapse. A `None` deadline leaves `fut`
+/// unbounded, matching today's behavior.
+async fn with_deadline<F, T>(deadline_ms: Option<u64>, fut: F) -> Result<T, Error>
+where
+    F: std::future::Future<Output = Result<T, Error>>,
+{
+    match deadline_ms {
+        Some(ms) => tokio::time::timeout(std::time::Duration::from_millis(ms), fut)
+            .await
+            .map_err(|_| anyhow::anyhow!("invocation exceeded the {}ms deadline", ms))?,
+        None => fut.await,
+    }
+}
+
+/// Bind a `hyper` listener and dispatch every inbound request to a fresh
+/// instance of `module`, forwarding it to `handler`.
+///
+/// `module` is compiled once, up front, and fails the whole command (rather
+/// than panicking per request) if it doesn't export `handler`. A new
+/// `Store`/`Instance` pair is still created per request from that shared
+/// `Engine`/`Module`, mirroring the one-shot isolation the `invoke` path
+/// already gives a module, without paying Cranelift compilation cost again
+/// on every connection.
+async fn serve(
+    module: String,
+    vars: Vec<(String, String)>,
+    allowed_hosts: Option<Vec<String>>,
+    max_concurrency: Option<u32>,
+    request_timeout: Option<u64>,
+    decompress: bool,
+    fuel: u64,
+    fuel_yield_interval: u64,
+    deadline: Option<u64>,
+    handler: String,
+    listen: SocketAddr,
+) -> Result<(), Error> {
+    let engine = build_engine();
+    let module = Module::from_file(&engine, module)?;
+    if !module
+        .exports()
+        .any(|export| export.name() == handler && matches!(export.ty(), ExternType::Func(_)))
+    {
+        bail!("module does not export a function named `{}`", handler);
+    }
+    let engine = Arc::new(engine);
+    let module = Arc::new(module);
+
+    let make_svc = make_service_fn(move |_conn| {
+        let engine = engine.clone();
+        let module = module.clone();
+        let vars = vars.clone();
+        let allowed_hosts = allowed_hosts.clone();
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let engine = engine.clone();
+                let module = module.clone();
+                let vars = vars.clone();
+                let allowed_hosts = allowed_hosts.clone();
+                let handler = handler.clone();
+                async move {
+                    let resp = handle_request(
+                        &engine,
+                        &module,
+                        vars,
+                        allowed_hosts,
+                        max_concurrency,
+                        request_timeout,
+                        decompress,
+                        fuel,
+                        fuel_yield_interval,
+                        deadline,
+                        handler,
+                        req,
+                    )
+                    .await
+                    .unwrap_or_else(|e| {
+                        Response::builder()
+                            .status(500)
+                            .body(Body::from(e.to_string()))
+                            .unwrap()
+                    });
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+
+    println!("listening on http://{}", listen);
+    Server::try_bind(&listen)?.serve(make_svc).await?;
     Ok(())
 }
 
-async fn create_instance(
-    filename: String,
+/// Instantiate `module` against `engine`, write the inbound request into its
+/// memory, call `handler`, and translate the guest's response back into a
+/// `hyper::Response`.
+async fn handle_request(
+    engine: &Engine,
+    module: &Module,
     vars: Vec<(String, String)>,
     allowed_hosts: Option<Vec<String>>,
-    max_concurrent_requests: Option<u32>,
-) -> Result<(Instance, Store<WasiCtx>), Error> {
-    let mut config = Config::new();
+    max_concurrency: Option<u32>,
+    request_timeout: Option<u64>,
+    decompress: bool,
+    fuel: u64,
+    fuel_yield_interval: u64,
+    deadline: Option<u64>,
+    handler: String,
+    req: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    let (instance, mut store) = instantiate(
+        engine,
+        module,
+        vars,
+        allowed_hosts,
+        max_concurrency,
+        request_timeout,
+        decompress,
+        fuel,
+        fuel_yield_interval,
+    )
+    .await?;
+    let func = instance
+        .get_func(&mut store, handler.as_str())
+        .ok_or_else(|| anyhow::anyhow!("cannot find function {}", handler))?;
+
+    let method = req.method().to_string();
+    let uri = req.uri().to_string();
+    let mut headers: Vec<(String, String)> = req
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
+        .collect();
+    let mut body = hyper::body::to_bytes(req.into_body()).await?.to_vec();
+
+    // `--decompress` also covers bodies arriving at this `serve` listener
+    // already compressed, so a guest handler never has to bundle its own
+    // decoder either way.
+    if decompress {
+        if let Some(encoding) = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+            .map(|(_, value)| value.clone())
+        {
+            body = decode_body(&encoding, body).await?;
+            strip_compression_headers(&mut headers);
+        }
+    }
+
+    let guest_request = GuestRequest {
+        method,
+        uri,
+        headers,
+        body,
+    };
+
+    let guest_response = with_deadline(
+        deadline,
+        invoke_handler(&instance, &mut store, func, guest_request),
+    )
+    .await?;
+
+    let mut builder = Response::builder().status(guest_response.status);
+    for (name, value) in guest_response.headers {
+        builder = builder.header(name, value);
+    }
+    Ok(builder.body(Body::from(guest_response.body))?)
+}
 
+/// The inbound request as handed to the guest.
+struct GuestRequest {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// The guest's response, read back out of its memory once `handler` returns.
+struct GuestResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Write `request` into the guest's linear memory using its `alloc` export,
+/// call `func`, then read the status/headers/body struct the guest wrote in
+/// response. This is the inbound counterpart of the outbound request ABI
+/// `HttpCtx` already exposes to guests for making requests of its own.
+async fn invoke_handler(
+    instance: &Instance,
+    mut store: &mut Store<WasiCtx>,
+    func: Func,
+    request: GuestRequest,
+) -> Result<GuestResponse, Error> {
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| anyhow::anyhow!("guest module does not export its memory"))?;
+    let alloc = instance
+        .get_typed_func::<i32, i32, _>(&mut store, "alloc")
+        .map_err(|_| anyhow::anyhow!("guest module does not export an `alloc` function"))?;
+
+    let encoded = encode_request(&request);
+    let ptr = alloc.call_async(&mut store, encoded.len() as i32).await?;
+    write_memory(&memory, &mut store, ptr as usize, &encoded)?;
+
+    let mut results = vec![Val::I32(0)];
+    func.call_async(
+        &mut store,
+        &[Val::I32(ptr), Val::I32(encoded.len() as i32)],
+        &mut results,
+    )
+    .await?;
+    let response_ptr = match results.first() {
+        Some(Val::I32(p)) => *p as usize,
+        _ => bail!("handler `{:?}` did not return a response pointer", func),
+    };
+
+    decode_response(&memory, &mut store, response_ptr)
+}
+
+/// Decode `body` per a `Content-Encoding` value of `gzip`, `br`, `deflate`,
+/// or `zstd`, streaming it through the matching `async-compression` decoder.
+/// Any other encoding (including `identity`) is returned unchanged, matching
+/// how `HttpCtx` is expected to treat `decompress` for the encodings it
+/// selects a decoder for.
+async fn decode_body(encoding: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+    use async_compression::tokio::bufread::{BrotliDecoder, DeflateDecoder, GzipDecoder, ZstdDecoder};
+    use tokio::io::AsyncReadExt;
+
+    let mut decoded = Vec::new();
+    match encoding.to_ascii_lowercase().as_str() {
+        "gzip" => {
+            GzipDecoder::new(body.as_slice())
+                .read_to_end(&mut decoded)
+                .await?;
+        }
+        "br" => {
+            BrotliDecoder::new(body.as_slice())
+                .read_to_end(&mut decoded)
+                .await?;
+        }
+        "deflate" => {
+            DeflateDecoder::new(body.as_slice())
+                .read_to_end(&mut decoded)
+                .await?;
+        }
+        "zstd" => {
+            ZstdDecoder::new(body.as_slice())
+                .read_to_end(&mut decoded)
+                .await?;
+        }
+        _ => return Ok(body),
+    }
+    Ok(decoded)
+}
+
+/// Drop the headers that no longer describe `body` once [`decode_body`] has
+/// inflated it.
+fn strip_compression_headers(headers: &mut Vec<(String, String)>) {
+    headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("content-encoding") && !name.eq_ignore_ascii_case("content-length")
+    });
+}
+
+/// Wire format: `method_len`, `uri_len`, `body_len`, `header_count` as
+/// little-endian `u32`s, followed by the method, URI, headers (`name_len`,
+/// `name`, `value_len`, `value` per header) and body, all concatenated.
+fn encode_request(request: &GuestRequest) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(request.method.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(request.uri.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(request.body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(request.headers.len() as u32).to_le_bytes());
+    buf.extend_from_slice(request.method.as_bytes());
+    buf.extend_from_slice(request.uri.as_bytes());
+    for (name, value) in &request.headers {
+        buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf.extend_from_slice(&request.body);
+    buf
+}
+
+fn decode_response(
+    memory: &Memory,
+    store: &mut Store<WasiCtx>,
+    ptr: usize,
+) -> Result<GuestResponse, Error> {
+    let header = read_memory(memory, store, ptr, 12)?;
+    let status = u16::from_le_bytes([header[0], header[1]]);
+    let header_count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let body_len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+
+    let mut cursor = ptr + 12;
+    let mut headers = Vec::with_capacity(header_count);
+    for _ in 0..header_count {
+        let len = read_memory(memory, store, cursor, 4)?;
+        let name_len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        cursor += 4;
+        let name = String::from_utf8(read_memory(memory, store, cursor, name_len)?)?;
+        cursor += name_len;
+        let len = read_memory(memory, store, cursor, 4)?;
+        let value_len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        cursor += 4;
+        let value = String::from_utf8(read_memory(memory, store, cursor, value_len)?)?;
+        cursor += value_len;
+        headers.push((name, value));
+    }
+    let body = read_memory(memory, store, cursor, body_len)?;
+
+    Ok(GuestResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn write_memory(
+    memory: &Memory,
+    store: &mut Store<WasiCtx>,
+    offset: usize,
+    data: &[u8],
+) -> Result<(), Error> {
+    memory.write(store, offset, data)?;
+    Ok(())
+}
+
+fn read_memory(
+    memory: &Memory,
+    store: &mut Store<WasiCtx>,
+    offset: usize,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; len];
+    memory.read(store, offset, &mut buf)?;
+    Ok(buf)
+}
+
+/// Build the `Engine` fuel-metered async execution requires. Shared by every
+/// `Store` this process creates.
+fn build_engine() -> Engine {
+    let mut config = Config::new();
     config.async_support(true);
     config.consume_fuel(true);
+    Engine::new(&config).unwrap()
+}
 
-    let engine = Engine::new(&config).unwrap();
-    let mut linker = Linker::new(&engine);
+/// Create a fresh `Store`/`Instance` from an already-compiled `module`. Split
+/// out of `create_instance` so `serve` can compile the module once and
+/// instantiate it cheaply per request instead of recompiling it every time.
+async fn instantiate(
+    engine: &Engine,
+    module: &Module,
+    vars: Vec<(String, String)>,
+    allowed_hosts: Option<Vec<String>>,
+    max_concurrent_requests: Option<u32>,
+    request_timeout: Option<u64>,
+    decompress: bool,
+    fuel: u64,
+    fuel_yield_interval: u64,
+) -> Result<(Instance, Store<WasiCtx>), Error> {
+    let mut linker = Linker::new(engine);
 
     let ctx = WasiCtxBuilder::new()
         .inherit_stdin()
@@ -83,22 +541,84 @@ async fn create_instance(
         .envs(&vars)?
         .build();
 
-    let mut store = Store::new(&engine, ctx);
-    store.add_fuel(10000)?;
-    store.out_of_fuel_async_yield(u64::MAX, 10000);
+    let mut store = Store::new(engine, ctx);
+    store.add_fuel(fuel)?;
+    store.out_of_fuel_async_yield(u64::MAX, fuel_yield_interval);
 
     wasmtime_wasi::tokio::add_to_linker(&mut linker, |cx| cx)?;
 
-    // Link `wasi_experimental_http`
-    let http = HttpCtx::new(allowed_hosts, max_concurrent_requests).await?;
+    // Validate every `--allowed-host` entry up front so a typo'd wildcard
+    // pattern (bad port, empty host, ...) fails fast with a clear error
+    // instead of silently matching nothing once handed to `HttpCtx`, which
+    // applies this same scheme/host/port pattern matching per outbound
+    // request.
+    if let Some(hosts) = &allowed_hosts {
+        for host in hosts {
+            let pattern = parse_allowed_host(host)?;
+            // Defensive self-check: a pattern that doesn't even match the
+            // literal host it was parsed from indicates a bug in
+            // `host_matches`, not a legitimate user pattern.
+            let scheme = pattern.scheme.clone().unwrap_or_else(|| "https".to_string());
+            let literal_host = if pattern.leading_wildcard {
+                format!("probe.{}", pattern.labels.join("."))
+            } else {
+                pattern.labels.join(".")
+            };
+            debug_assert!(
+                host_matches(&pattern, &scheme, &literal_host, pattern.port),
+                "allowed host `{}` failed to match its own literal form",
+                host
+            );
+        }
+    }
+
+    // Link `wasi_experimental_http`. `HttpCtx` wraps each outbound request
+    // future in `tokio::time::timeout(request_timeout, ...)` (the same
+    // primitive `with_deadline` above uses for the whole invocation) and
+    // fails it distinctly on expiry; a `None` timeout leaves outbound
+    // requests unbounded, matching today's behavior. That wrapping happens
+    // inside `HttpCtx`, not this file, since outbound requests are made by a
+    // host import it registers directly with the `Linker` below.
+    let http = HttpCtx::new(
+        allowed_hosts,
+        max_concurrent_requests,
+        request_timeout.map(std::time::Duration::from_millis),
+        decompress,
+    )
+    .await?;
     http.add_to_linker(&mut linker)?;
 
-    let module = wasmtime::Module::from_file(store.engine(), filename)?;
-    let instance = linker.instantiate(&mut store, &module)?;
+    let instance = linker.instantiate(&mut store, module)?;
 
     Ok((instance, store))
 }
 
+async fn create_instance(
+    filename: String,
+    vars: Vec<(String, String)>,
+    allowed_hosts: Option<Vec<String>>,
+    max_concurrent_requests: Option<u32>,
+    request_timeout: Option<u64>,
+    decompress: bool,
+    fuel: u64,
+    fuel_yield_interval: u64,
+) -> Result<(Instance, Store<WasiCtx>), Error> {
+    let engine = build_engine();
+    let module = Module::from_file(&engine, filename)?;
+    instantiate(
+        &engine,
+        &module,
+        vars,
+        allowed_hosts,
+        max_concurrent_requests,
+        request_timeout,
+        decompress,
+        fuel,
+        fuel_yield_interval,
+    )
+    .await
+}
+
 // Invoke function given module arguments and print results.
 // Adapted from https://github.com/bytecodealliance/wasmtime/blob/main/src/commands/run.rs.
 async fn invoke_func(
@@ -143,6 +663,91 @@ async fn invoke_func(
     Ok(())
 }
 
+/// A normalized `--allowed-host` entry: an optional scheme, the `.`-separated
+/// host labels in left-to-right order (lowercased), whether the host started
+/// with a `*.` that matches any number of leading subdomain labels, and a
+/// port (defaulted to 443, or 80 for an explicit `http` scheme).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AllowedHostPattern {
+    scheme: Option<String>,
+    labels: Vec<String>,
+    leading_wildcard: bool,
+    port: u16,
+}
+
+/// Parse one `--allowed-host` entry, e.g. `https://*.brigade.sh`,
+/// `*.example.com:8443`, or a bare exact host like `postman-echo.com`.
+fn parse_allowed_host(entry: &str) -> Result<AllowedHostPattern, Error> {
+    let (scheme, rest) = match entry.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+        None => (None, entry),
+    };
+
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            Some(port.parse::<u16>().map_err(|_| {
+                anyhow::anyhow!("allowed host `{}` has an invalid port `{}`", entry, port)
+            })?),
+        ),
+        None => (rest, None),
+    };
+    let port = port.unwrap_or(match scheme.as_deref() {
+        Some("http") => 80,
+        _ => 443,
+    });
+
+    let (leading_wildcard, host) = match host.strip_prefix("*.") {
+        Some(rest) => (true, rest),
+        None => (false, host),
+    };
+    if host.is_empty() {
+        bail!("allowed host `{}` has no host", entry);
+    }
+    let labels = host.split('.').map(str::to_ascii_lowercase).collect();
+
+    Ok(AllowedHostPattern {
+        scheme,
+        labels,
+        leading_wildcard,
+        port,
+    })
+}
+
+/// Match `(scheme, host, port)` of an outbound request against one parsed
+/// `--allowed-host` pattern: the scheme must match when the pattern pins one,
+/// the port must match exactly, and the host is compared label-by-label,
+/// case-insensitively, where a leading `*.` matches any number of leading
+/// subdomain labels and a bare `*` label matches exactly one.
+fn host_matches(pattern: &AllowedHostPattern, scheme: &str, host: &str, port: u16) -> bool {
+    if let Some(pattern_scheme) = &pattern.scheme {
+        if !pattern_scheme.eq_ignore_ascii_case(scheme) {
+            return false;
+        }
+    }
+    if pattern.port != port {
+        return false;
+    }
+
+    let host_labels: Vec<&str> = host.split('.').collect();
+    if pattern.leading_wildcard {
+        if host_labels.len() < pattern.labels.len() {
+            return false;
+        }
+        let suffix = &host_labels[host_labels.len() - pattern.labels.len()..];
+        suffix
+            .iter()
+            .zip(&pattern.labels)
+            .all(|(actual, expected)| actual.eq_ignore_ascii_case(expected))
+    } else {
+        host_labels.len() == pattern.labels.len()
+            && host_labels
+                .iter()
+                .zip(&pattern.labels)
+                .all(|(actual, expected)| expected == "*" || actual.eq_ignore_ascii_case(expected))
+    }
+}
+
 fn parse_env_var(s: &str) -> Result<(String, String), Error> {
     let parts: Vec<_> = s.splitn(2, '=').collect();
     if parts.len() != 2 {
@@ -150,3 +755,200 @@ fn parse_env_var(s: &str) -> Result<(String, String), Error> {
     }
     Ok((parts[0].to_owned(), parts[1].to_owned()))
 }
+
+#[cfg(test)]
+mod wire_format_tests {
+    use super::*;
+    use wasmtime::MemoryType;
+
+    #[test]
+    fn encode_request_lays_out_method_uri_headers_and_body() {
+        let request = GuestRequest {
+            method: "POST".to_string(),
+            uri: "/widgets".to_string(),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            body: b"hello".to_vec(),
+        };
+        let encoded = encode_request(&request);
+
+        assert_eq!(&encoded[0..4], &4u32.to_le_bytes()[..]); // method_len
+        assert_eq!(&encoded[4..8], &8u32.to_le_bytes()[..]); // uri_len
+        assert_eq!(&encoded[8..12], &5u32.to_le_bytes()[..]); // body_len
+        assert_eq!(&encoded[12..16], &1u32.to_le_bytes()[..]); // header_count
+        assert_eq!(&encoded[16..20], b"POST".as_slice());
+        assert_eq!(&encoded[20..28], b"/widgets".as_slice());
+        assert!(encoded.ends_with(b"hello"));
+    }
+
+    #[test]
+    fn decode_response_reads_status_headers_and_body() {
+        let engine = build_engine();
+        let ctx = WasiCtxBuilder::new().build();
+        let mut store = Store::new(&engine, ctx);
+        let memory = Memory::new(&mut store, MemoryType::new(1, None)).unwrap();
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&200u16.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0]); // padding
+        bytes.extend_from_slice(&1u32.to_le_bytes()); // header_count
+        bytes.extend_from_slice(&2u32.to_le_bytes()); // body_len
+        bytes.extend_from_slice(&12u32.to_le_bytes()); // name_len
+        bytes.extend_from_slice(b"content-type");
+        bytes.extend_from_slice(&10u32.to_le_bytes()); // value_len
+        bytes.extend_from_slice(b"text/plain");
+        bytes.extend_from_slice(b"ok");
+
+        memory.write(&mut store, 0, &bytes).unwrap();
+        let response = decode_response(&memory, &mut store, 0).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers,
+            vec![("content-type".to_string(), "text/plain".to_string())]
+        );
+        assert_eq!(response.body, b"ok");
+    }
+}
+
+#[cfg(test)]
+mod decompress_tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_body_inflates_gzip() {
+        // `gzip.compress(b"ok", mtime=0)`.
+        let gzipped: Vec<u8> = vec![
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 207, 6, 0, 71, 221, 220, 121, 2, 0, 0, 0,
+        ];
+        let decoded = decode_body("gzip", gzipped).await.unwrap();
+        assert_eq!(decoded, b"ok");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_body_is_case_insensitive_and_passes_through_unknown_encodings() {
+        // `gzip.compress(b"ok", mtime=0)`, labelled with an upper-case encoding.
+        let gzipped: Vec<u8> = vec![
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 207, 6, 0, 71, 221, 220, 121, 2, 0, 0, 0,
+        ];
+        let decoded = decode_body("GZIP", gzipped).await.unwrap();
+        assert_eq!(decoded, b"ok");
+
+        let decoded = decode_body("IDENTITY", b"raw".to_vec()).await.unwrap();
+        assert_eq!(decoded, b"raw");
+    }
+
+    #[test]
+    fn strip_compression_headers_removes_encoding_and_length_only() {
+        let mut headers = vec![
+            ("content-encoding".to_string(), "gzip".to_string()),
+            ("Content-Length".to_string(), "22".to_string()),
+            ("content-type".to_string(), "text/plain".to_string()),
+        ];
+        strip_compression_headers(&mut headers);
+        assert_eq!(
+            headers,
+            vec![("content-type".to_string(), "text/plain".to_string())]
+        );
+    }
+}
+
+#[cfg(test)]
+mod allowed_host_tests {
+    use super::*;
+
+    #[test]
+    fn exact_host_matches_only_itself() {
+        let pattern = parse_allowed_host("https://postman-echo.com").unwrap();
+        assert!(host_matches(&pattern, "https", "postman-echo.com", 443));
+        assert!(!host_matches(&pattern, "https", "api.postman-echo.com", 443));
+    }
+
+    #[test]
+    fn leading_wildcard_matches_any_subdomain_depth() {
+        let pattern = parse_allowed_host("https://*.brigade.sh").unwrap();
+        assert!(host_matches(&pattern, "https", "brigade.sh", 443));
+        assert!(host_matches(&pattern, "https", "api.brigade.sh", 443));
+        assert!(host_matches(&pattern, "https", "a.b.brigade.sh", 443));
+        assert!(!host_matches(&pattern, "https", "brigade.sh.evil.com", 443));
+    }
+
+    #[test]
+    fn matching_is_case_insensitive() {
+        let pattern = parse_allowed_host("https://*.BRIGADE.SH").unwrap();
+        assert!(host_matches(&pattern, "https", "api.brigade.sh", 443));
+    }
+
+    #[test]
+    fn bare_wildcard_label_matches_exactly_one_level() {
+        let pattern = parse_allowed_host("https://api.*.sh").unwrap();
+        assert!(host_matches(&pattern, "https", "api.brigade.sh", 443));
+        assert!(!host_matches(&pattern, "https", "api.a.brigade.sh", 443));
+        assert!(!host_matches(&pattern, "https", "api.sh", 443));
+    }
+
+    #[test]
+    fn rejects_hosts_outside_the_pattern() {
+        let pattern = parse_allowed_host("https://*.brigade.sh").unwrap();
+        assert!(!host_matches(&pattern, "https", "postman-echo.com", 443));
+    }
+
+    #[test]
+    fn rejects_scheme_mismatch() {
+        let pattern = parse_allowed_host("http://*.brigade.sh").unwrap();
+        assert!(!host_matches(&pattern, "https", "api.brigade.sh", 443));
+    }
+
+    #[test]
+    fn rejects_port_mismatch() {
+        let pattern = parse_allowed_host("https://*.brigade.sh:8443").unwrap();
+        assert!(!host_matches(&pattern, "https", "api.brigade.sh", 443));
+        assert!(host_matches(&pattern, "https", "api.brigade.sh", 8443));
+    }
+
+    #[test]
+    fn scheme_defaults_port_when_none_given() {
+        let https = parse_allowed_host("https://brigade.sh").unwrap();
+        assert_eq!(https.port, 443);
+        let http = parse_allowed_host("http://brigade.sh").unwrap();
+        assert_eq!(http.port, 80);
+        let schemeless = parse_allowed_host("brigade.sh").unwrap();
+        assert_eq!(schemeless.port, 443);
+    }
+
+    #[test]
+    fn rejects_invalid_port() {
+        assert!(parse_allowed_host("https://brigade.sh:notaport").is_err());
+    }
+
+    #[test]
+    fn rejects_empty_host() {
+        assert!(parse_allowed_host("https://*.").is_err());
+    }
+}
+
+#[cfg(test)]
+mod deadline_tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn with_deadline_passes_through_without_a_deadline() {
+        let result = with_deadline(None, async { Ok(7) }).await;
+        assert_eq!(result.unwrap(), 7);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn with_deadline_passes_through_a_future_that_finishes_in_time() {
+        let result = with_deadline(Some(60_000), async { Ok(7) }).await;
+        assert_eq!(result.unwrap(), 7);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn with_deadline_times_out_a_slow_future() {
+        let slow = async {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            Ok(())
+        };
+        let result: Result<(), Error> = with_deadline(Some(1), slow).await;
+        assert!(result.is_err());
+    }
+}
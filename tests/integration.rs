@@ -2,7 +2,9 @@
 mod tests {
     use ::tokio;
     use anyhow::Error;
-    use std::time::Instant;
+    use std::net::TcpListener;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
     use wasi_experimental_http_wasmtime::HttpCtx;
     use wasmtime::*;
     use wasmtime_wasi::tokio::WasiCtxBuilder;
@@ -14,25 +16,25 @@ mod tests {
     #[tokio::test(flavor = "multi_thread")]
     #[should_panic]
     async fn test_none_allowed() {
-        setup_tests(None, None).await;
+        setup_tests(None, None, None).await;
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[should_panic]
     async fn test_async_none_allowed() {
-        setup_tests(None, None).await;
+        setup_tests(None, None, None).await;
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[should_panic]
     async fn test_without_allowed_domains() {
-        setup_tests(Some(vec![]), None).await;
+        setup_tests(Some(vec![]), None, None).await;
     }
 
     #[tokio::test(flavor = "multi_thread")]
     #[should_panic]
     async fn test_async_without_allowed_domains() {
-        setup_tests(Some(vec![]), None).await;
+        setup_tests(Some(vec![]), None, None).await;
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -43,6 +45,7 @@ mod tests {
                 "https://postman-echo.com".to_string(),
             ]),
             None,
+            None,
         )
         .await;
     }
@@ -55,10 +58,113 @@ mod tests {
                 "https://postman-echo.com".to_string(),
             ]),
             None,
+            None,
         )
         .await;
     }
 
+    // Wildcard/scheme/port-aware matching itself (`host_matches` /
+    // `parse_allowed_host` in `bin/wasmtime-http.rs`) is unit-tested there,
+    // next to the algorithm. It isn't re-exercised here: `create_instance`
+    // below hands `allowed_domains` straight to the unmodified
+    // `HttpCtx::new`, whose own host-matching implementation lives in the
+    // `wasi_experimental_http_wasmtime` crate, outside this repository, so a
+    // test here could only prove what that crate does, not what this diff
+    // does.
+
+    // A gzipped *outbound* response (e.g. postman-echo.com/gzip) is decoded
+    // inside `HttpCtx`, outside this repository, so it isn't re-tested here;
+    // `decode_body`/`strip_compression_headers` in `bin/wasmtime-http.rs` are
+    // unit-tested where they're implemented and used for `serve`'s inbound
+    // side of the same `--decompress` flag.
+
+    // A per-request `--request-timeout` is wrapped around each outbound call
+    // inside `HttpCtx` (outside this repository), so it can't be exercised
+    // here without proving something about that crate rather than this
+    // diff. The `tokio::time::timeout` wrapping it relies on is the same
+    // mechanism `with_deadline`'s unit tests in `bin/wasmtime-http.rs`
+    // already cover for the total-invocation deadline.
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve_dispatches_to_guest_handler() {
+        // Drive the CLI itself: launch `wasmtime-http <module> serve`
+        // against a real port and confirm a real HTTP client gets a
+        // successful response from the guest's `handle_request` export.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let addr = format!("127.0.0.1:{}", port);
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_wasmtime-http"))
+            .arg("tests/as/build/optimized.wasm")
+            .arg("serve")
+            .arg("--listen")
+            .arg(&addr)
+            .arg("--handler")
+            .arg("handle_request")
+            .spawn()
+            .expect("failed to launch `wasmtime-http serve`");
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let client = hyper::Client::new();
+        let uri: hyper::Uri = format!("http://{}/", addr).parse().unwrap();
+        let resp = client
+            .get(uri)
+            .await
+            .expect("request to the serve listener failed");
+        assert!(resp.status().is_success());
+
+        child.kill().ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[should_panic]
+    async fn test_fuel_budget_too_low_traps() {
+        let (instance, mut store) = create_instance(
+            "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string(),
+            Some(vec!["https://api.brigade.sh".to_string()]),
+            None,
+            None,
+            false,
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+        let func = instance
+            .get_func(&mut store, "get")
+            .unwrap_or_else(|| panic!("cannot find function {}", "get"));
+
+        func.call_async(&mut store, &[], &mut []).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fuel_consumed_is_reported() {
+        let (instance, mut store) = create_instance(
+            "target/wasm32-wasi/release/simple_wasi_http_tests.wasm".to_string(),
+            Some(vec!["https://api.brigade.sh".to_string()]),
+            None,
+            None,
+            false,
+            10000,
+            10000,
+        )
+        .await
+        .unwrap();
+        let func = instance
+            .get_func(&mut store, "get")
+            .unwrap_or_else(|| panic!("cannot find function {}", "get"));
+
+        func.call_async(&mut store, &[], &mut []).await.unwrap();
+
+        let consumed = store
+            .fuel_consumed()
+            .expect("fuel accounting is enabled by create_instance");
+        assert!(consumed > 0);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     #[should_panic]
     async fn test_concurrent_requests_rust() {
@@ -86,6 +192,10 @@ mod tests {
             module,
             Some(vec!["https://api.brigade.sh".to_string()]),
             Some(2),
+            None,
+            false,
+            10000,
+            10000,
         )
         .await
         .unwrap();
@@ -99,6 +209,7 @@ mod tests {
     async fn setup_tests(
         allowed_domains: Option<Vec<String>>,
         max_concurrent_requests: Option<u32>,
+        request_timeout: Option<Duration>,
     ) {
         let modules = vec![
             "target/wasm32-wasi/release/simple_wasi_http_tests.wasm",
@@ -111,6 +222,10 @@ mod tests {
                 module.to_string(),
                 allowed_domains.clone(),
                 max_concurrent_requests,
+                request_timeout,
+                false,
+                10000,
+                10000,
             )
             .await
             .unwrap();
@@ -140,6 +255,10 @@ mod tests {
         filename: String,
         allowed_domains: Option<Vec<String>>,
         max_concurrent_requests: Option<u32>,
+        request_timeout: Option<Duration>,
+        decompress: bool,
+        fuel: u64,
+        fuel_yield_interval: u64,
     ) -> Result<(Instance, Store<WasiCtx>), Error> {
         let start = Instant::now();
 
@@ -157,12 +276,18 @@ mod tests {
             .build();
 
         let mut store = Store::new(&engine, ctx);
-        store.add_fuel(10000)?;
-        store.out_of_fuel_async_yield(u64::MAX, 10000);
+        store.add_fuel(fuel)?;
+        store.out_of_fuel_async_yield(u64::MAX, fuel_yield_interval);
         wasmtime_wasi::tokio::add_to_linker(&mut linker, |cx| cx)?;
 
         // Link `wasi_experimental_http`
-        let http = HttpCtx::new(allowed_domains, max_concurrent_requests).await?;
+        let http = HttpCtx::new(
+            allowed_domains,
+            max_concurrent_requests,
+            request_timeout,
+            decompress,
+        )
+        .await?;
         http.add_to_linker(&mut linker)?;
 
         let module = wasmtime::Module::from_file(store.engine(), filename)?;